@@ -31,19 +31,28 @@ extern crate winapi;
 extern crate dxgi;
 extern crate d3d11;
 
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
 use std::mem;
 use std::ptr::{ self, Unique };
 use std::time::duration::Duration;
 use libc::c_void;
-use winapi::{ HRESULT, IID, DWORD, RECT, HMONITOR, BOOL };
+use winapi::{ HRESULT, IID, DWORD, RECT, HMONITOR, BOOL, HANDLE };
 use dxgi::constants::*;
 use dxgi::interfaces::*;
-use dxgi::{ DXGI_OUTPUT_DESC };
+use dxgi::{ DXGI_OUTPUT_DESC, DXGI_OUTDUPL_FRAME_INFO, DXGI_OUTDUPL_MOVE_RECT,
+	DXGI_OUTDUPL_POINTER_POSITION, DXGI_OUTDUPL_POINTER_SHAPE_INFO, DXGI_OUTDUPL_POINTER_SHAPE_TYPE,
+	DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MONOCHROME, DXGI_OUTDUPL_POINTER_SHAPE_TYPE_COLOR,
+	DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MASKED_COLOR,
+	DXGI_MAPPED_RECT, DXGI_FORMAT, DXGI_ERROR_MORE_DATA, DXGI_ERROR_NOT_FOUND,
+	DXGI_ERROR_ACCESS_LOST, DXGI_ERROR_DEVICE_REMOVED, DXGI_ERROR_DEVICE_RESET,
+	DXGI_ERROR_UNSUPPORTED, DXGI_ERROR_WAIT_TIMEOUT, CreateDXGIFactory1, IID_IDXGIFactory1,
+	IID_IDXGIOutput1, IID_IDXGIDevice1, IID_IDXGIResource, IID_IDXGIKeyedMutex };
 use d3d11::constants::*;
 use d3d11::core::interfaces::*;
 use d3d11::resource::interfaces::*;
-use d3d11::{ D3D11_USAGE, D3D11_CPU_ACCESS_FLAG };
+use d3d11::{ D3D11_USAGE, D3D11_CPU_ACCESS_FLAG, D3D_DRIVER_TYPE, D3D11_SDK_VERSION,
+	D3D_FEATURE_LEVEL, D3D11CreateDevice, IID_ID3D11Device, D3D11_BIND_FLAG,
+	D3D11_RESOURCE_MISC_FLAG };
 
 #[repr(C)] struct MONITORINFO {
 	cbSize: DWORD,
@@ -57,6 +66,32 @@ extern "C" {
 	fn GetMonitorInfoW(monitor: HMONITOR, monitor_info: *mut MONITORINFO) -> BOOL;
 }
 
+#[link(name = "kernel32")]
+extern "C" {
+	fn QueryPerformanceFrequency(frequency: *mut i64) -> BOOL;
+}
+
+static QPC_FREQUENCY_INIT: Once = ONCE_INIT;
+static mut QPC_FREQUENCY: i64 = 0;
+
+/// Ticks-per-second of the counter behind `DXGI_OUTDUPL_FRAME_INFO::LastPresentTime`, fetched
+/// once and cached: `QueryPerformanceFrequency` is documented to never change while the system
+/// is running.
+fn qpc_frequency() -> i64 {
+	unsafe {
+		QPC_FREQUENCY_INIT.call_once(|| { QueryPerformanceFrequency(&mut QPC_FREQUENCY); });
+		QPC_FREQUENCY
+	}
+}
+
+/// Converts a `QueryPerformanceCounter` tick count to a `Duration` since an arbitrary, fixed
+/// epoch (typically system boot), using the frequency from `qpc_frequency`.
+fn qpc_to_duration(ticks: i64, frequency: i64) -> Duration {
+	let whole_seconds = ticks / frequency;
+	let remainder_nanos = (ticks % frequency) * 1_000_000_000 / frequency;
+	Duration::seconds(whole_seconds) + Duration::nanoseconds(remainder_nanos)
+}
+
 /// A unique pointer to a COM object. Handles refcounting.
 pub struct UniqueCOMPtr<T: IUnknownT> {
 	ptr: Unique<T>,
@@ -79,6 +114,21 @@ impl<T: IUnknownT> UniqueCOMPtr<T> {
 			Ok(UniqueCOMPtr::from_ptr(interface as *mut U))
 		}
 	}
+
+	/// Like `query_interface`, but takes the pointer by reference instead of by value, so
+	/// several independent interfaces onto the same COM object can be held at once. Each call
+	/// bumps the object's refcount, same as any other `QueryInterface`.
+	pub unsafe fn query_interface_ref<U>(&mut self, interface_identifier: &IID)
+		-> Result<UniqueCOMPtr<U>, HRESULT> where U: IUnknownT
+	{
+		let mut interface: *mut c_void = ptr::null_mut();
+		let hr = self.QueryInterface(interface_identifier, &mut interface);
+		if hr_failed(hr) {
+			Err(hr)
+		} else {
+			Ok(UniqueCOMPtr::from_ptr(interface as *mut U))
+		}
+	}
 }
 impl<T: IUnknownT> std::ops::Deref for UniqueCOMPtr<T> {
 	type Target = T;
@@ -104,6 +154,85 @@ unsafe impl<T> Send for UniqueCOMPtr<T> { }
 
 pub fn hr_failed(hr: HRESULT) -> bool { hr < 0 }
 
+/// Classifies the `HRESULT`s that `DXGIManager` actually has to act on, so callers don't need to
+/// memorize DXGI error constants. Anything not specifically classified falls back to `Hr`, which
+/// still carries the raw code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaptureError {
+	/// `AcquireNextFrame` didn't produce a frame within the configured timeout.
+	Timeout,
+	/// `DXGI_ERROR_ACCESS_LOST`; the duplicated output was invalidated by something like a mode
+	/// change, desktop switch, or secure-desktop transition, but the device itself is fine.
+	AccessLost,
+	/// `DXGI_ERROR_DEVICE_REMOVED`; the D3D11 device was lost and must be recreated.
+	DeviceRemoved,
+	/// `DXGI_ERROR_DEVICE_RESET`; the D3D11 device was reset and must be recreated.
+	DeviceReset,
+	/// The duplicated surface format has no entry in `FORMAT_TABLE`, or a DXGI call returned
+	/// `DXGI_ERROR_UNSUPPORTED`.
+	Unsupported,
+	/// Any other failing `HRESULT`, carried unclassified.
+	Hr(HRESULT),
+}
+impl From<HRESULT> for CaptureError {
+	fn from(hr: HRESULT) -> CaptureError {
+		match hr {
+			DXGI_ERROR_WAIT_TIMEOUT => CaptureError::Timeout,
+			DXGI_ERROR_ACCESS_LOST => CaptureError::AccessLost,
+			DXGI_ERROR_DEVICE_REMOVED => CaptureError::DeviceRemoved,
+			DXGI_ERROR_DEVICE_RESET => CaptureError::DeviceReset,
+			DXGI_ERROR_UNSUPPORTED => CaptureError::Unsupported,
+			hr => CaptureError::Hr(hr),
+		}
+	}
+}
+impl std::fmt::Display for CaptureError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match *self {
+			CaptureError::Timeout => write!(f, "timed out waiting for a new frame"),
+			CaptureError::AccessLost => write!(f, "duplicated output access lost"),
+			CaptureError::DeviceRemoved => write!(f, "D3D11 device removed"),
+			CaptureError::DeviceReset => write!(f, "D3D11 device reset"),
+			CaptureError::Unsupported => write!(f, "unsupported surface format"),
+			CaptureError::Hr(hr) => write!(f, "DXGI/D3D11 call failed with HRESULT {:#x}", hr),
+		}
+	}
+}
+impl std::error::Error for CaptureError {
+	fn description(&self) -> &str {
+		match *self {
+			CaptureError::Timeout => "timed out waiting for a new frame",
+			CaptureError::AccessLost => "duplicated output access lost",
+			CaptureError::DeviceRemoved => "D3D11 device removed",
+			CaptureError::DeviceReset => "D3D11 device reset",
+			CaptureError::Unsupported => "unsupported surface format",
+			CaptureError::Hr(_) => "DXGI/D3D11 call failed",
+		}
+	}
+}
+
+#[test]
+fn capture_error_from_classifies_known_hresults() {
+	assert_eq!(CaptureError::from(DXGI_ERROR_WAIT_TIMEOUT), CaptureError::Timeout);
+	assert_eq!(CaptureError::from(DXGI_ERROR_ACCESS_LOST), CaptureError::AccessLost);
+	assert_eq!(CaptureError::from(DXGI_ERROR_DEVICE_REMOVED), CaptureError::DeviceRemoved);
+	assert_eq!(CaptureError::from(DXGI_ERROR_DEVICE_RESET), CaptureError::DeviceReset);
+	assert_eq!(CaptureError::from(DXGI_ERROR_UNSUPPORTED), CaptureError::Unsupported);
+}
+
+#[test]
+fn capture_error_from_falls_back_to_hr() {
+	assert_eq!(CaptureError::from(-1), CaptureError::Hr(-1));
+}
+
+#[test]
+fn capture_error_display_formats_hr_as_hex() {
+	let hr = 0x887a0005u32 as HRESULT;
+	assert_eq!(format!("{}", CaptureError::Hr(hr)),
+		format!("DXGI/D3D11 call failed with HRESULT {:#x}", hr));
+	assert_eq!(format!("{}", CaptureError::Timeout), "timed out waiting for a new frame");
+}
+
 pub fn get_adater_outputs(adapter: &mut IDXGIAdapter1) -> Vec<UniqueCOMPtr<IDXGIOutput>> {
 	(0..).map(|i| {
 			let mut output = ptr::null_mut();
@@ -120,11 +249,39 @@ pub fn get_adater_outputs(adapter: &mut IDXGIAdapter1) -> Vec<UniqueCOMPtr<IDXGI
 		.collect()
 }
 
+/// A region of the previous frame that moved intact to a new location in the current frame, as
+/// reported by `IDXGIOutputDuplication::GetFrameMoveRects`.
+#[derive(Clone, Copy, Debug)]
+pub struct MoveRect {
+	pub source_point: (i32, i32),
+	pub destination_rect: RECT,
+}
+
+/// A cached cursor bitmap from `IDXGIOutputDuplication::GetFramePointerShape`. The shape is only
+/// resent when it actually changes (`PointerShapeBufferSize != 0`), so the last one received is
+/// kept around and reused on every frame where the cursor is visible.
+struct PointerShape {
+	info: DXGI_OUTDUPL_POINTER_SHAPE_INFO,
+	bytes: Vec<u8>,
+}
+
+/// Per-frame metadata returned alongside the captured surface.
+struct FrameMetadata {
+	format: DXGI_FORMAT,
+	pointer_position: DXGI_OUTDUPL_POINTER_POSITION,
+	move_rects: Vec<MoveRect>,
+	dirty_rects: Vec<RECT>,
+	/// When the frame was presented, as a `Duration` since an arbitrary fixed epoch (derived
+	/// from `DXGI_OUTDUPL_FRAME_INFO::LastPresentTime`, a `QueryPerformanceCounter` value).
+	presented_at: Duration,
+}
+
 struct DuplicatedOutput {
 	device: Arc<Mutex<UniqueCOMPtr<ID3D11Device>>>,
 	device_context: Arc<Mutex<UniqueCOMPtr<ID3D11DeviceContext>>>,
 	output: UniqueCOMPtr<IDXGIOutput1>,
 	dxgi_output_dup: UniqueCOMPtr<IDXGIOutputDuplication>,
+	cached_pointer_shape: Option<PointerShape>,
 }
 impl DuplicatedOutput {
 	fn get_desc(&mut self) -> DXGI_OUTPUT_DESC {
@@ -133,23 +290,43 @@ impl DuplicatedOutput {
 		desc
 	}
 
-	fn get_frame(&mut self, timeout: Duration) -> Result<UniqueCOMPtr<IDXGISurface1>, HRESULT> {
+	fn get_frame(&mut self, timeout: Duration)
+		-> Result<(UniqueCOMPtr<IDXGISurface1>, FrameMetadata), CaptureError>
+	{
+		let mut frame_info = unsafe { mem::zeroed() };
 		let frame_resource = unsafe {
 			let mut frame_resource = ptr::null_mut();
-			let mut frame_info = mem::zeroed();
 			let hr = self.dxgi_output_dup.AcquireNextFrame(timeout.num_milliseconds() as u32,
 				&mut frame_info,
 				&mut frame_resource);
 			if hr_failed(hr) {
-				return Err(hr);
+				return Err(hr.into());
 			}
 			UniqueCOMPtr::from_ptr(frame_resource) };
 
+		// The shape is only resent when it changes; a zero buffer size means the last shape we
+		// cached (if any) is still current.
+		if frame_info.PointerShapeBufferSize != 0 {
+			try!(self.cache_pointer_shape(frame_info.PointerShapeBufferSize));
+		}
+
+		// `AccumulatedFrames == 0` (equivalently a zero `LastPresentTime`) means the desktop
+		// didn't actually change since the last `AcquireNextFrame`, so there is no metadata to
+		// fetch and the rect lists are trivially empty.
+		let (move_rects, dirty_rects) = if frame_info.AccumulatedFrames == 0
+			|| frame_info.TotalMetadataBufferSize == 0
+		{
+			(Vec::new(), Vec::new())
+		} else {
+			try!(self.get_frame_rects(&frame_info))
+		};
+
 		let mut frame_texture: UniqueCOMPtr<ID3D11Texture2D> = unsafe {
 			frame_resource.query_interface(&IID_ID3D11Texture2D).unwrap() };
 
 		let mut texture_desc = unsafe { mem::zeroed() };
 		frame_texture.GetDesc(&mut texture_desc);
+		let format = texture_desc.Format;
 
 		// Configure the description to make the texture readable
 		texture_desc.Usage = D3D11_USAGE::D3D11_USAGE_STAGING;
@@ -162,7 +339,7 @@ impl DuplicatedOutput {
 			let hr = self.device.lock().unwrap()
 				.CreateTexture2D(&mut texture_desc, ptr::null(), &mut readable_texture);
 			if hr_failed(hr) {
-				return Err(hr);
+				return Err(hr.into());
 			}
 			UniqueCOMPtr::from_ptr(readable_texture) };
 
@@ -176,12 +353,202 @@ impl DuplicatedOutput {
 			.CopyResource(&mut *readable_surface,
 				&mut *unsafe { frame_texture.query_interface(&IID_ID3D11Resource).unwrap() });
 
-		unsafe { readable_surface.query_interface(&IID_IDXGISurface1) }
+		let readable_surface = try!(unsafe { readable_surface.query_interface(&IID_IDXGISurface1) });
+		Ok((readable_surface, FrameMetadata {
+			format: format,
+			pointer_position: frame_info.PointerPosition,
+			move_rects: move_rects,
+			dirty_rects: dirty_rects,
+			presented_at: qpc_to_duration(frame_info.LastPresentTime, qpc_frequency()),
+		}))
+	}
+
+	/// Fetches and caches the cursor bitmap named by `GetFramePointerShape`, growing the
+	/// scratch buffer and retrying as long as DXGI reports `DXGI_ERROR_MORE_DATA`.
+	fn cache_pointer_shape(&mut self, buffer_size_hint: u32) -> Result<(), HRESULT> {
+		let mut buf: Vec<u8> = Vec::with_capacity(buffer_size_hint as usize);
+		let mut shape_info = unsafe { mem::zeroed() };
+		let used_len = loop {
+			let capacity = buf.capacity();
+			let mut used_len = 0;
+			let hr = unsafe {
+				self.dxgi_output_dup.GetFramePointerShape(capacity as u32,
+					buf.as_mut_ptr() as *mut c_void,
+					&mut used_len,
+					&mut shape_info) };
+			if hr == DXGI_ERROR_MORE_DATA {
+				buf.reserve(capacity + 1);
+				continue;
+			} else if hr_failed(hr) {
+				return Err(hr);
+			}
+			break used_len;
+		};
+		unsafe { buf.set_len(used_len as usize) };
+
+		self.cached_pointer_shape = Some(PointerShape { info: shape_info, bytes: buf });
+		Ok(())
+	}
+
+	/// Fetch the per-frame move-rect and dirty-rect metadata described by `frame_info`, growing
+	/// the scratch buffers and retrying as long as DXGI reports `DXGI_ERROR_MORE_DATA`.
+	fn get_frame_rects(&mut self, frame_info: &DXGI_OUTDUPL_FRAME_INFO)
+		-> Result<(Vec<MoveRect>, Vec<RECT>), HRESULT>
+	{
+		let mut move_rect_buf: Vec<DXGI_OUTDUPL_MOVE_RECT> =
+			Vec::with_capacity(frame_info.TotalMetadataBufferSize as usize
+				/ mem::size_of::<DXGI_OUTDUPL_MOVE_RECT>() + 1);
+		let move_rect_count = loop {
+			let capacity = move_rect_buf.capacity();
+			let buf_len = (capacity * mem::size_of::<DXGI_OUTDUPL_MOVE_RECT>()) as u32;
+			let mut used_len = 0;
+			let hr = unsafe {
+				self.dxgi_output_dup.GetFrameMoveRects(buf_len,
+					move_rect_buf.as_mut_ptr(),
+					&mut used_len) };
+			if hr == DXGI_ERROR_MORE_DATA {
+				move_rect_buf.reserve(capacity + 1);
+				continue;
+			} else if hr_failed(hr) {
+				return Err(hr);
+			}
+			break used_len as usize / mem::size_of::<DXGI_OUTDUPL_MOVE_RECT>();
+		};
+		unsafe { move_rect_buf.set_len(move_rect_count) };
+		let move_rects: Vec<MoveRect> = move_rect_buf.iter()
+			.map(|r| MoveRect {
+				source_point: (r.SourcePoint.x, r.SourcePoint.y),
+				destination_rect: r.DestinationRect })
+			.collect();
+
+		let mut dirty_rect_buf: Vec<RECT> =
+			Vec::with_capacity(frame_info.TotalMetadataBufferSize as usize
+				/ mem::size_of::<RECT>() + 1);
+		let dirty_rect_count = loop {
+			let capacity = dirty_rect_buf.capacity();
+			let buf_len = (capacity * mem::size_of::<RECT>()) as u32;
+			let mut used_len = 0;
+			let hr = unsafe {
+				self.dxgi_output_dup.GetFrameDirtyRects(buf_len,
+					dirty_rect_buf.as_mut_ptr(),
+					&mut used_len) };
+			if hr == DXGI_ERROR_MORE_DATA {
+				dirty_rect_buf.reserve(capacity + 1);
+				continue;
+			} else if hr_failed(hr) {
+				return Err(hr);
+			}
+			break used_len as usize / mem::size_of::<RECT>();
+		};
+		unsafe { dirty_rect_buf.set_len(dirty_rect_count) };
+
+		Ok((move_rects, dirty_rect_buf))
+	}
+
+	/// Like `get_frame`, but instead of copying the frame down to a CPU-readable staging
+	/// texture, copies it into a GPU-resident texture shared via a keyed mutex and hands back
+	/// the shared handle. The caller (or a downstream process that opens the handle on its own
+	/// device) synchronizes access with `IDXGIKeyedMutex::AcquireSync`/`ReleaseSync` on key `0`,
+	/// so the frame never touches system RAM.
+	fn get_frame_shared(&mut self, timeout: Duration) -> Result<SharedFrame, CaptureError> {
+		let mut frame_info = unsafe { mem::zeroed() };
+		let frame_resource = unsafe {
+			let mut frame_resource = ptr::null_mut();
+			let hr = self.dxgi_output_dup.AcquireNextFrame(timeout.num_milliseconds() as u32,
+				&mut frame_info,
+				&mut frame_resource);
+			if hr_failed(hr) {
+				return Err(hr.into());
+			}
+			UniqueCOMPtr::from_ptr(frame_resource) };
+
+		let (move_rects, dirty_rects) = if frame_info.AccumulatedFrames == 0
+			|| frame_info.TotalMetadataBufferSize == 0
+		{
+			(Vec::new(), Vec::new())
+		} else {
+			try!(self.get_frame_rects(&frame_info))
+		};
+
+		let mut frame_texture: UniqueCOMPtr<ID3D11Texture2D> = unsafe {
+			frame_resource.query_interface(&IID_ID3D11Texture2D).unwrap() };
+
+		let mut texture_desc = unsafe { mem::zeroed() };
+		frame_texture.GetDesc(&mut texture_desc);
+		let format = texture_desc.Format;
+		let (width, height) = (texture_desc.Width, texture_desc.Height);
+
+		// Configure the description for a GPU-resident destination shared via a keyed mutex,
+		// rather than a CPU-readable staging texture.
+		texture_desc.Usage = D3D11_USAGE::D3D11_USAGE_DEFAULT;
+		texture_desc.BindFlags = D3D11_BIND_FLAG::D3D11_BIND_SHADER_RESOURCE as u32;
+		texture_desc.CPUAccessFlags = 0;
+		texture_desc.MiscFlags = D3D11_RESOURCE_MISC_FLAG::D3D11_RESOURCE_MISC_SHARED_KEYEDMUTEX as u32;
+
+		let mut shared_texture = unsafe {
+			let mut shared_texture = ptr::null_mut();
+			let hr = self.device.lock().unwrap()
+				.CreateTexture2D(&mut texture_desc, ptr::null(), &mut shared_texture);
+			if hr_failed(hr) {
+				return Err(hr.into());
+			}
+			UniqueCOMPtr::from_ptr(shared_texture) };
+
+		let mut keyed_mutex: UniqueCOMPtr<IDXGIKeyedMutex> = unsafe {
+			try!(shared_texture.query_interface_ref(&IID_IDXGIKeyedMutex)) };
+		let hr = unsafe { keyed_mutex.AcquireSync(0, KEYED_MUTEX_INFINITE_TIMEOUT) };
+		if hr_failed(hr) {
+			return Err(hr.into());
+		}
+
+		self.device_context.lock().unwrap()
+			.CopyResource(&mut *unsafe { shared_texture.query_interface_ref(&IID_ID3D11Resource).unwrap() },
+				&mut *unsafe { frame_texture.query_interface(&IID_ID3D11Resource).unwrap() });
+
+		let hr = unsafe { keyed_mutex.ReleaseSync(0) };
+		if hr_failed(hr) {
+			return Err(hr.into());
+		}
+
+		let shared_handle = unsafe {
+			let mut dxgi_resource: UniqueCOMPtr<IDXGIResource> =
+				try!(shared_texture.query_interface_ref(&IID_IDXGIResource));
+			let mut shared_handle = ptr::null_mut();
+			let hr = dxgi_resource.GetSharedHandle(&mut shared_handle);
+			if hr_failed(hr) {
+				return Err(hr.into());
+			}
+			shared_handle };
+
+		Ok(SharedFrame { texture: shared_texture, shared_handle: shared_handle, format: format,
+			width: width, height: height, move_rects: move_rects, dirty_rects: dirty_rects })
 	}
 
-	fn release_frame(&mut self) -> Result<(), HRESULT> {
+	fn release_frame(&mut self) -> Result<(), CaptureError> {
 		let hr = self.dxgi_output_dup.ReleaseFrame();
-		if hr_failed(hr) { Err(hr) } else { Ok(()) }
+		if hr_failed(hr) { Err(hr.into()) } else { Ok(()) }
+	}
+
+	/// Re-run `DuplicateOutput` against the output using the device that's already set up,
+	/// without tearing the device down. This is enough to recover from `DXGI_ERROR_ACCESS_LOST`,
+	/// which is raised by things like a mode change, desktop switch, or secure-desktop
+	/// transition rather than an actual loss of the device.
+	fn recreate_duplication(&mut self) -> Result<(), CaptureError> {
+		let mut dxgi_device: UniqueCOMPtr<IDXGIDevice1> = unsafe {
+			try!(self.device.lock().unwrap().query_interface_ref(&IID_IDXGIDevice1)) };
+
+		let dxgi_output_dup = unsafe {
+			let mut dxgi_output_dup: *mut IDXGIOutputDuplication = ptr::null_mut();
+			let hr = self.output.DuplicateOutput(
+				mem::transmute::<&mut IDXGIDevice1, _>(&mut dxgi_device),
+				&mut dxgi_output_dup);
+			if hr_failed(hr) {
+				return Err(hr.into());
+			}
+			UniqueCOMPtr::from_ptr(dxgi_output_dup) };
+
+		self.dxgi_output_dup = dxgi_output_dup;
+		Ok(())
 	}
 
 	fn is_primary(&mut self) -> bool {
@@ -197,13 +564,674 @@ impl DuplicatedOutput {
 	}
 }
 
+/// A single captured pixel in the order DXGI hands it back: blue, green, red, alpha.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BGRA8 {
+	pub b: u8,
+	pub g: u8,
+	pub r: u8,
+	pub a: u8,
+}
+
+/// Per-channel byte layout of a mapped pixel: how many bytes it takes up, and which byte holds
+/// which channel. Needed to interpret the mapped row pitch correctly and to convert a readback
+/// down to 8-bit BGRA.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ChannelOrder {
+	Bgra8,
+	Rgba8,
+	Rgba16Float,
+}
+
+/// Bytes-per-pixel and channel order for every surface format `DXGIManager` knows how to read
+/// back. Desktop Duplication normally hands back `B8G8R8A8_UNORM`, but HDR/wide-gamut outputs
+/// duplicate as `R16G16B16A16_FLOAT` (scRGB) instead.
+const FORMAT_TABLE: &'static [(DXGI_FORMAT, usize, ChannelOrder)] = &[
+	(DXGI_FORMAT::DXGI_FORMAT_B8G8R8A8_UNORM, 4, ChannelOrder::Bgra8),
+	(DXGI_FORMAT::DXGI_FORMAT_R8G8B8A8_UNORM, 4, ChannelOrder::Rgba8),
+	(DXGI_FORMAT::DXGI_FORMAT_R16G16B16A16_FLOAT, 8, ChannelOrder::Rgba16Float),
+];
+
+fn format_layout(format: DXGI_FORMAT) -> Result<(usize, ChannelOrder), CaptureError> {
+	FORMAT_TABLE.iter()
+		.find(|&&(table_format, _, _)| table_format as u32 == format as u32)
+		.map(|&(_, bytes_per_pixel, channel_order)| (bytes_per_pixel, channel_order))
+		.ok_or(CaptureError::Unsupported)
+}
+
+#[test]
+fn format_layout_known_formats() {
+	assert_eq!(format_layout(DXGI_FORMAT::DXGI_FORMAT_B8G8R8A8_UNORM).unwrap(),
+		(4, ChannelOrder::Bgra8));
+	assert_eq!(format_layout(DXGI_FORMAT::DXGI_FORMAT_R8G8B8A8_UNORM).unwrap(),
+		(4, ChannelOrder::Rgba8));
+	assert_eq!(format_layout(DXGI_FORMAT::DXGI_FORMAT_R16G16B16A16_FLOAT).unwrap(),
+		(8, ChannelOrder::Rgba16Float));
+}
+
+#[test]
+fn format_layout_unknown_format_is_unsupported() {
+	assert_eq!(format_layout(DXGI_FORMAT::DXGI_FORMAT_UNKNOWN), Err(CaptureError::Unsupported));
+}
+
+/// Decodes an IEEE-754 binary16 value, the per-channel representation used by
+/// `R16G16B16A16_FLOAT`, to `f32`.
+fn half_to_f32(half: u16) -> f32 {
+	let sign = (half >> 15) & 0x1;
+	let exponent = (half >> 10) & 0x1f;
+	let mantissa = (half & 0x3ff) as f32;
+
+	let magnitude = if exponent == 0 {
+		mantissa * 2f32.powi(-24)
+	} else if exponent == 0x1f {
+		if mantissa == 0.0 { std::f32::INFINITY } else { std::f32::NAN }
+	} else {
+		(1.0 + mantissa / 1024.0) * 2f32.powi(exponent as i32 - 15)
+	};
+
+	if sign == 1 { -magnitude } else { magnitude }
+}
+
+#[test]
+fn half_to_f32_decodes_common_values() {
+	assert_eq!(half_to_f32(0x0000), 0.0);
+	assert_eq!(half_to_f32(0x3c00), 1.0);
+	assert_eq!(half_to_f32(0xbc00), -1.0);
+	assert_eq!(half_to_f32(0x3800), 0.5);
+}
+
+#[test]
+fn half_to_f32_decodes_subnormals() {
+	// Smallest positive subnormal: mantissa = 1, exponent = 0.
+	assert_eq!(half_to_f32(0x0001), 2f32.powi(-24));
+}
+
+#[test]
+fn half_to_f32_decodes_infinity_and_nan() {
+	assert_eq!(half_to_f32(0x7c00), std::f32::INFINITY);
+	assert_eq!(half_to_f32(0xfc00), std::f32::NEG_INFINITY);
+	assert!(half_to_f32(0x7c01).is_nan());
+}
+
+/// A raw captured frame, in whatever format the duplicated output produced it in. Desktop
+/// Duplication normally yields `B8G8R8A8_UNORM`, but HDR/wide-gamut outputs duplicate as
+/// `R16G16B16A16_FLOAT` (scRGB) instead, so `format` must be checked (or `to_bgra8` used) before
+/// interpreting `data`.
+pub struct CapturedFrame {
+	pub data: Vec<u8>,
+	pub width: usize,
+	pub height: usize,
+	pub format: DXGI_FORMAT,
+	/// When this frame was presented, as a `Duration` since an arbitrary fixed epoch (typically
+	/// system boot). Derived from `DXGI_OUTDUPL_FRAME_INFO::LastPresentTime`, so it's only
+	/// meaningful relative to other timestamps produced the same way, not as wall-clock time.
+	pub presented_at: Duration,
+	/// Regions of the previous frame that moved intact to a new location in this frame. Empty
+	/// when DXGI reported no metadata for this frame (e.g. the desktop didn't change).
+	pub move_rects: Vec<MoveRect>,
+	/// Regions of this frame that changed relative to the previous one, so a caller that keeps
+	/// its own copy of the previous frame can apply a partial update instead of the whole frame.
+	/// Empty when DXGI reported no metadata for this frame.
+	pub dirty_rects: Vec<RECT>,
+}
+impl CapturedFrame {
+	/// Converts the raw readback to 8-bit BGRA, tone-mapping `R16G16B16A16_FLOAT` scRGB content
+	/// down to sRGB with a simple clamp and gamma curve; formats that are already 8 bits per
+	/// channel are just reordered into BGRA.
+	pub fn to_bgra8(&self) -> Result<Vec<BGRA8>, CaptureError> {
+		let (bytes_per_pixel, channel_order) = try!(format_layout(self.format));
+		let to_srgb8 = |linear: f32| (linear.max(0.0).min(1.0).powf(1.0 / 2.2) * 255.0) as u8;
+
+		Ok(self.data.chunks(bytes_per_pixel)
+			.take(self.width * self.height)
+			.map(|p| match channel_order {
+				ChannelOrder::Bgra8 => BGRA8 { b: p[0], g: p[1], r: p[2], a: p[3] },
+				ChannelOrder::Rgba8 => BGRA8 { b: p[2], g: p[1], r: p[0], a: p[3] },
+				ChannelOrder::Rgba16Float => {
+					let channel = |i: usize| half_to_f32((p[2 * i] as u16) | ((p[2 * i + 1] as u16) << 8));
+					BGRA8 {
+						b: to_srgb8(channel(2)),
+						g: to_srgb8(channel(1)),
+						r: to_srgb8(channel(0)),
+						a: (channel(3).max(0.0).min(1.0) * 255.0) as u8,
+					}
+				}
+			})
+			.collect())
+	}
+}
+
+#[test]
+fn to_bgra8_reorders_bgra8_passthrough() {
+	let frame = CapturedFrame {
+		data: vec![1, 2, 3, 4],
+		width: 1,
+		height: 1,
+		format: DXGI_FORMAT::DXGI_FORMAT_B8G8R8A8_UNORM,
+		presented_at: Duration::seconds(0),
+		move_rects: Vec::new(),
+		dirty_rects: Vec::new(),
+	};
+	assert_eq!(frame.to_bgra8().unwrap(), vec![BGRA8 { b: 1, g: 2, r: 3, a: 4 }]);
+}
+
+#[test]
+fn to_bgra8_reorders_rgba8() {
+	let frame = CapturedFrame {
+		data: vec![1, 2, 3, 4],
+		width: 1,
+		height: 1,
+		format: DXGI_FORMAT::DXGI_FORMAT_R8G8B8A8_UNORM,
+		presented_at: Duration::seconds(0),
+		move_rects: Vec::new(),
+		dirty_rects: Vec::new(),
+	};
+	assert_eq!(frame.to_bgra8().unwrap(), vec![BGRA8 { b: 3, g: 2, r: 1, a: 4 }]);
+}
+
+#[test]
+fn to_bgra8_tone_maps_rgba16_float() {
+	// R=G=B=1.0 (0x3c00), A=1.0 (0x3c00), little-endian half-floats.
+	let data = vec![0x00, 0x3c, 0x00, 0x3c, 0x00, 0x3c, 0x00, 0x3c];
+	let frame = CapturedFrame {
+		data: data,
+		width: 1,
+		height: 1,
+		format: DXGI_FORMAT::DXGI_FORMAT_R16G16B16A16_FLOAT,
+		presented_at: Duration::seconds(0),
+		move_rects: Vec::new(),
+		dirty_rects: Vec::new(),
+	};
+	assert_eq!(frame.to_bgra8().unwrap(), vec![BGRA8 { b: 255, g: 255, r: 255, a: 255 }]);
+}
+
+#[test]
+fn to_bgra8_rejects_unsupported_format() {
+	let frame = CapturedFrame {
+		data: Vec::new(),
+		width: 0,
+		height: 0,
+		format: DXGI_FORMAT::DXGI_FORMAT_UNKNOWN,
+		presented_at: Duration::seconds(0),
+		move_rects: Vec::new(),
+		dirty_rects: Vec::new(),
+	};
+	assert_eq!(frame.to_bgra8().unwrap_err(), CaptureError::Unsupported);
+}
+
+/// Blends a cached cursor shape into a mapped 8-bit-per-channel pixel buffer at `position`,
+/// clipping against the output bounds. `channel_order` must be `Bgra8` or `Rgba8`; HDR
+/// (`Rgba16Float`) readbacks are left uncomposited, since the cursor shape DXGI hands back is
+/// always 8 bits per channel and blending it into a linear scRGB buffer would require tone-mapping
+/// it back up, which this simple compositor doesn't attempt.
+fn composite_cursor(data: &mut [u8], width: usize, height: usize, bytes_per_pixel: usize,
+	channel_order: ChannelOrder, shape: &PointerShape, position: &DXGI_OUTDUPL_POINTER_POSITION)
+{
+	let (r, g, b) = match channel_order {
+		ChannelOrder::Bgra8 => (2, 1, 0),
+		ChannelOrder::Rgba8 => (0, 1, 2),
+		ChannelOrder::Rgba16Float => return,
+	};
+
+	let info = &shape.info;
+	if info.Width == 0 || info.Height == 0 || info.Pitch == 0 {
+		return;
+	}
+
+	let (cursor_width, cursor_height, monochrome) =
+		if info.Type == DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MONOCHROME {
+			(info.Width as usize, info.Height as usize / 2, true)
+		} else {
+			(info.Width as usize, info.Height as usize, false)
+		};
+
+	let origin_x = position.Position.x - info.HotSpot.x;
+	let origin_y = position.Position.y - info.HotSpot.y;
+
+	for cursor_y in 0..cursor_height {
+		let dst_y = origin_y + cursor_y as i32;
+		if dst_y < 0 || dst_y as usize >= height {
+			continue;
+		}
+
+		for cursor_x in 0..cursor_width {
+			let dst_x = origin_x + cursor_x as i32;
+			if dst_x < 0 || dst_x as usize >= width {
+				continue;
+			}
+
+			let dst_offset = (dst_y as usize * width + dst_x as usize) * bytes_per_pixel;
+			let dst_pixel = &mut data[dst_offset..dst_offset + bytes_per_pixel];
+
+			if monochrome {
+				let pitch = info.Pitch as usize;
+				let byte_index = cursor_x / 8;
+				let bit_mask = 0x80 >> (cursor_x % 8);
+				let and_byte = shape.bytes[cursor_y * pitch + byte_index];
+				let xor_byte = shape.bytes[(cursor_height + cursor_y) * pitch + byte_index];
+				let and_bit = and_byte & bit_mask != 0;
+				let xor_bit = xor_byte & bit_mask != 0;
+
+				// AND 1, XOR 0 => leave the pixel untouched (transparent); AND 1, XOR 1 => invert
+				// the existing pixel; AND 0 => opaque black or white, from XOR.
+				if and_bit && !xor_bit {
+					continue;
+				} else if and_bit && xor_bit {
+					dst_pixel[r] = !dst_pixel[r];
+					dst_pixel[g] = !dst_pixel[g];
+					dst_pixel[b] = !dst_pixel[b];
+				} else {
+					let value = if xor_bit { 0xff } else { 0x00 };
+					dst_pixel[r] = value;
+					dst_pixel[g] = value;
+					dst_pixel[b] = value;
+				}
+			} else {
+				let pitch = info.Pitch as usize;
+				let src_offset = cursor_y * pitch + cursor_x * 4;
+				let src_pixel = &shape.bytes[src_offset..src_offset + 4];
+				let alpha = src_pixel[3];
+
+				if info.Type == DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MASKED_COLOR && alpha == 0xff {
+					// A mask (alpha) value of 0xff selects the XOR case: XOR the colour bits into
+					// the destination, leaving its bits untouched where the cursor's bits are zero.
+					dst_pixel[r] ^= src_pixel[2];
+					dst_pixel[g] ^= src_pixel[1];
+					dst_pixel[b] ^= src_pixel[0];
+				} else if info.Type == DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MASKED_COLOR {
+					// Any other mask (alpha) value selects the "copy" case: replace the
+					// destination's colour outright.
+					dst_pixel[r] = src_pixel[2];
+					dst_pixel[g] = src_pixel[1];
+					dst_pixel[b] = src_pixel[0];
+				} else {
+					// Straight alpha-blend for DXGI_OUTDUPL_POINTER_SHAPE_TYPE_COLOR.
+					let blend = |dst: u8, src: u8| {
+						((src as u32 * alpha as u32 + dst as u32 * (255 - alpha as u32)) / 255) as u8
+					};
+					dst_pixel[r] = blend(dst_pixel[r], src_pixel[2]);
+					dst_pixel[g] = blend(dst_pixel[g], src_pixel[1]);
+					dst_pixel[b] = blend(dst_pixel[b], src_pixel[0]);
+				}
+			}
+		}
+	}
+}
+
+/// Builds a monochrome (1bpp AND/XOR) `PointerShape` for a `width`x`height` cursor from the given
+/// per-row AND/XOR bit patterns, one `u8` bitmap byte per row (MSB-first), for use in tests.
+#[cfg(test)]
+fn monochrome_shape(width: u32, height: u32, and_rows: &[u8], xor_rows: &[u8]) -> PointerShape {
+	let mut info: DXGI_OUTDUPL_POINTER_SHAPE_INFO = unsafe { mem::zeroed() };
+	info.Type = DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MONOCHROME;
+	info.Width = width;
+	info.Height = height * 2;
+	info.Pitch = 1;
+	let mut bytes = Vec::new();
+	bytes.extend_from_slice(and_rows);
+	bytes.extend_from_slice(xor_rows);
+	PointerShape { info: info, bytes: bytes }
+}
+
+/// Builds a 32bpp color (or masked-color) `PointerShape` for a 1x1 cursor from a single BGRA
+/// pixel, for use in tests.
+#[cfg(test)]
+fn color_shape(shape_type: DXGI_OUTDUPL_POINTER_SHAPE_TYPE, bgra: [u8; 4]) -> PointerShape {
+	let mut info: DXGI_OUTDUPL_POINTER_SHAPE_INFO = unsafe { mem::zeroed() };
+	info.Type = shape_type;
+	info.Width = 1;
+	info.Height = 1;
+	info.Pitch = 4;
+	PointerShape { info: info, bytes: bgra.to_vec() }
+}
+
+#[cfg(test)]
+fn pointer_position(x: i32, y: i32) -> DXGI_OUTDUPL_POINTER_POSITION {
+	let mut position: DXGI_OUTDUPL_POINTER_POSITION = unsafe { mem::zeroed() };
+	position.Position.x = x;
+	position.Position.y = y;
+	position.Visible = 1;
+	position
+}
+
+#[test]
+fn composite_cursor_monochrome_and1_xor0_leaves_pixel_untouched() {
+	let shape = monochrome_shape(1, 1, &[0x80], &[0x00]);
+	let mut data = vec![10u8, 20, 30, 40];
+	composite_cursor(&mut data, 1, 1, 4, ChannelOrder::Bgra8, &shape, &pointer_position(0, 0));
+	assert_eq!(data, vec![10, 20, 30, 40]);
+}
+
+#[test]
+fn composite_cursor_monochrome_and1_xor1_inverts_pixel() {
+	let shape = monochrome_shape(1, 1, &[0x80], &[0x80]);
+	let mut data = vec![10u8, 20, 30, 40];
+	composite_cursor(&mut data, 1, 1, 4, ChannelOrder::Bgra8, &shape, &pointer_position(0, 0));
+	assert_eq!(data, vec![!10u8, !20u8, !30u8, 40]);
+}
+
+#[test]
+fn composite_cursor_monochrome_and0_xor1_paints_white() {
+	let shape = monochrome_shape(1, 1, &[0x00], &[0x80]);
+	let mut data = vec![10u8, 20, 30, 40];
+	composite_cursor(&mut data, 1, 1, 4, ChannelOrder::Bgra8, &shape, &pointer_position(0, 0));
+	assert_eq!(data, vec![0xff, 0xff, 0xff, 40]);
+}
+
+#[test]
+fn composite_cursor_monochrome_and0_xor0_paints_black() {
+	let shape = monochrome_shape(1, 1, &[0x00], &[0x00]);
+	let mut data = vec![10u8, 20, 30, 40];
+	composite_cursor(&mut data, 1, 1, 4, ChannelOrder::Bgra8, &shape, &pointer_position(0, 0));
+	assert_eq!(data, vec![0x00, 0x00, 0x00, 40]);
+}
+
+#[test]
+fn composite_cursor_masked_color_xors_when_alpha_is_0xff() {
+	// B, G, R, A
+	let shape = color_shape(DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MASKED_COLOR, [0x0f, 0xf0, 0xaa, 0xff]);
+	let mut data = vec![0xffu8, 0x0f, 0x55, 40];
+	composite_cursor(&mut data, 1, 1, 4, ChannelOrder::Bgra8, &shape, &pointer_position(0, 0));
+	assert_eq!(data, vec![0xff ^ 0x0f, 0x0f ^ 0xf0, 0x55 ^ 0xaa, 40]);
+}
+
+#[test]
+fn composite_cursor_masked_color_copies_when_alpha_is_not_0xff() {
+	// B, G, R, A — alpha 0x00 selects the "copy" case, which must replace the destination's
+	// colour outright rather than leaving it untouched (as a blend with alpha == 0 would).
+	let shape = color_shape(DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MASKED_COLOR, [0x0f, 0xf0, 0xaa, 0x00]);
+	let mut data = vec![0xffu8, 0x0f, 0x55, 40];
+	composite_cursor(&mut data, 1, 1, 4, ChannelOrder::Bgra8, &shape, &pointer_position(0, 0));
+	assert_eq!(data, vec![0x0f, 0xf0, 0xaa, 40]);
+}
+
+#[test]
+fn composite_cursor_color_alpha_blends() {
+	// Half (0x80) alpha blend of a pure-white source over a black destination should land near
+	// the midpoint of the channel range.
+	let shape = color_shape(DXGI_OUTDUPL_POINTER_SHAPE_TYPE_COLOR, [0xff, 0xff, 0xff, 0x80]);
+	let mut data = vec![0x00u8, 0x00, 0x00, 40];
+	composite_cursor(&mut data, 1, 1, 4, ChannelOrder::Bgra8, &shape, &pointer_position(0, 0));
+	assert_eq!(data, vec![0x80, 0x80, 0x80, 40]);
+}
+
+#[test]
+fn composite_cursor_clips_against_output_bounds() {
+	let shape = monochrome_shape(1, 1, &[0x00], &[0x80]);
+	let mut data = vec![10u8, 20, 30, 40];
+	// Position the cursor entirely off the 1x1 output; nothing should be written.
+	composite_cursor(&mut data, 1, 1, 4, ChannelOrder::Bgra8, &shape, &pointer_position(5, 5));
+	assert_eq!(data, vec![10, 20, 30, 40]);
+}
+
+/// How many times `DXGIManager::capture_frame` will try to repair the capture session (via
+/// re-duplication or a full device recreation) before giving up and returning the error.
+const MAX_RECOVERY_ATTEMPTS: u32 = 3;
+
+/// Timeout passed to `IDXGIKeyedMutex::AcquireSync` while publishing a freshly created shared
+/// texture that nothing else can yet be contending for.
+const KEYED_MUTEX_INFINITE_TIMEOUT: DWORD = 0xFFFFFFFF;
+
+/// A frame handed off entirely on the GPU instead of read back to system RAM. `shared_handle`
+/// can be opened on another `ID3D11Device` (even in another process) and the two sides
+/// synchronize access via `IDXGIKeyedMutex::AcquireSync`/`ReleaseSync` on key `0`, e.g. to feed a
+/// hardware video encoder directly from the texture. `texture` is this side's own reference to
+/// the shared texture; it must be kept alive for as long as `shared_handle` is in use, since
+/// dropping the last reference releases the underlying GPU resource and invalidates the handle.
+pub struct SharedFrame {
+	pub texture: UniqueCOMPtr<ID3D11Texture2D>,
+	pub shared_handle: HANDLE,
+	pub format: DXGI_FORMAT,
+	pub width: u32,
+	pub height: u32,
+	/// Regions of the previous frame that moved intact to a new location in this frame. Empty
+	/// when DXGI reported no metadata for this frame (e.g. the desktop didn't change).
+	pub move_rects: Vec<MoveRect>,
+	/// Regions of this frame that changed relative to the previous one. Empty when DXGI reported
+	/// no metadata for this frame.
+	pub dirty_rects: Vec<RECT>,
+}
+
+/// Captures the desktop by keeping a single `DuplicatedOutput` alive across calls, transparently
+/// recovering from the access-lost and device-lost errors that routinely occur after a mode
+/// change, desktop switch, or GPU driver reset. This mirrors how desktop compositors keep a
+/// single resilient capture session alive across display topology changes rather than failing
+/// the whole pipeline.
+pub struct DXGIManager {
+	duplicated_output: DuplicatedOutput,
+	capture_source_index: usize,
+	timeout_ms: u32,
+	composite_cursor: bool,
+}
+impl DXGIManager {
+	pub fn new(timeout_ms: u64) -> Result<DXGIManager, &'static str> {
+		let duplicated_output = try!(Self::duplicate_output_at(0));
+		Ok(DXGIManager {
+			duplicated_output: duplicated_output,
+			capture_source_index: 0,
+			timeout_ms: timeout_ms as u32,
+			composite_cursor: false,
+		})
+	}
+
+	/// Desktop Duplication never includes the cursor in the captured image. When enabled,
+	/// `capture_frame` blends the last cursor shape DXGI reported into the pixel buffer at its
+	/// current position. Off by default.
+	pub fn set_composite_cursor(&mut self, enabled: bool) {
+		self.composite_cursor = enabled;
+	}
+
+	/// Selects which of the available display outputs to capture, across all adapters.
+	/// Takes effect on the next successful (re)acquisition of the duplicated output.
+	pub fn set_capture_source_index(&mut self, index: usize) {
+		self.capture_source_index = index;
+	}
+
+	pub fn geometry(&mut self) -> (usize, usize) {
+		Self::output_dimensions(&self.duplicated_output.get_desc())
+	}
+
+	fn output_dimensions(desc: &DXGI_OUTPUT_DESC) -> (usize, usize) {
+		((desc.DesktopCoordinates.right - desc.DesktopCoordinates.left) as usize,
+			(desc.DesktopCoordinates.bottom - desc.DesktopCoordinates.top) as usize)
+	}
+
+	pub fn capture_frame(&mut self) -> Result<CapturedFrame, CaptureError> {
+		self.retry_with_recovery(DXGIManager::try_capture_frame)
+	}
+
+	/// Like `capture_frame`, but hands the frame off on the GPU via a shared keyed-mutex
+	/// texture instead of reading it back to a CPU pixel buffer.
+	pub fn capture_frame_shared(&mut self) -> Result<SharedFrame, CaptureError> {
+		self.retry_with_recovery(DXGIManager::try_capture_frame_shared)
+	}
+
+	/// Runs `capture` and, on failure, attempts to repair the capture session and retries, up
+	/// to `MAX_RECOVERY_ATTEMPTS` times.
+	fn retry_with_recovery<T, F>(&mut self, mut capture: F) -> Result<T, CaptureError>
+		where F: FnMut(&mut DXGIManager) -> Result<T, CaptureError>
+	{
+		for attempt in 0..MAX_RECOVERY_ATTEMPTS + 1 {
+			match capture(self) {
+				Ok(frame) => return Ok(frame),
+				Err(err) => {
+					if attempt == MAX_RECOVERY_ATTEMPTS || !self.try_recover(err) {
+						return Err(err);
+					}
+				}
+			}
+		}
+		unreachable!()
+	}
+
+	fn try_capture_frame(&mut self) -> Result<CapturedFrame, CaptureError> {
+		let timeout = Duration::milliseconds(self.timeout_ms as i64);
+		let (mut surface, metadata) = try!(self.duplicated_output.get_frame(timeout));
+		let (width, height) = Self::output_dimensions(&self.duplicated_output.get_desc());
+		let (bytes_per_pixel, channel_order) = try!(format_layout(metadata.format));
+
+		let mut mapped_rect: DXGI_MAPPED_RECT = unsafe { mem::zeroed() };
+		let hr = unsafe { surface.Map(&mut mapped_rect, DXGI_MAP_READ) };
+		if hr_failed(hr) {
+			return Err(hr.into());
+		}
+
+		let row_bytes = width * bytes_per_pixel;
+		let mut data: Vec<u8> = unsafe {
+			(0..height as isize)
+				.flat_map(|row| {
+					let row_start = mapped_rect.pBits.offset(row * mapped_rect.Pitch as isize);
+					std::slice::from_raw_parts(row_start as *const u8, row_bytes).to_vec() })
+				.collect() };
+
+		surface.Unmap();
+		try!(self.duplicated_output.release_frame());
+
+		if self.composite_cursor && metadata.pointer_position.Visible != 0 {
+			if let Some(ref shape) = self.duplicated_output.cached_pointer_shape {
+				composite_cursor(&mut data, width, height, bytes_per_pixel, channel_order,
+					shape, &metadata.pointer_position);
+			}
+		}
+
+		Ok(CapturedFrame {
+			data: data,
+			width: width,
+			height: height,
+			format: metadata.format,
+			presented_at: metadata.presented_at,
+			move_rects: metadata.move_rects,
+			dirty_rects: metadata.dirty_rects,
+		})
+	}
+
+	fn try_capture_frame_shared(&mut self) -> Result<SharedFrame, CaptureError> {
+		let timeout = Duration::milliseconds(self.timeout_ms as i64);
+		let shared_frame = try!(self.duplicated_output.get_frame_shared(timeout));
+		try!(self.duplicated_output.release_frame());
+
+		Ok(shared_frame)
+	}
+
+	/// Attempts to repair the capture session after a fallible capture, returning whether the
+	/// caller should retry the capture.
+	fn try_recover(&mut self, err: CaptureError) -> bool {
+		match err {
+			CaptureError::AccessLost => self.duplicated_output.recreate_duplication().is_ok(),
+			CaptureError::DeviceRemoved | CaptureError::DeviceReset => {
+				let reason = self.duplicated_output.device.lock().unwrap().GetDeviceRemovedReason();
+				eprintln!("dxgcap: D3D11 device lost (reason {:#x}), recreating capture session",
+					reason);
+				self.acquire_output_duplication().is_ok()
+			}
+			_ => false,
+		}
+	}
+
+	/// Re-runs `duplicate_output_at` with the manager's current `capture_source_index` and
+	/// assigns the result in place. Only valid once `self.duplicated_output` already holds a
+	/// real (non-placeholder) value, i.e. for recovery after the initial `new()`; use
+	/// `duplicate_output_at` directly when no `DuplicatedOutput` exists yet to assign into.
+	fn acquire_output_duplication(&mut self) -> Result<(), &'static str> {
+		self.duplicated_output = try!(Self::duplicate_output_at(self.capture_source_index));
+		Ok(())
+	}
+
+	/// Re-enumerates adapters and outputs, creates a fresh `ID3D11Device`, and duplicates the
+	/// output at `capture_source_index`. Used both for the initial setup and to fully recover
+	/// from `DXGI_ERROR_DEVICE_REMOVED`/`DXGI_ERROR_DEVICE_RESET`.
+	fn duplicate_output_at(capture_source_index: usize) -> Result<DuplicatedOutput, &'static str> {
+		let mut factory = unsafe {
+			let mut factory: *mut c_void = ptr::null_mut();
+			if hr_failed(CreateDXGIFactory1(&IID_IDXGIFactory1, &mut factory)) {
+				return Err("Failed to create DXGI factory");
+			}
+			UniqueCOMPtr::from_ptr(factory as *mut IDXGIFactory1) };
+
+		let adapters: Vec<_> = (0..).map(|i| {
+				let mut adapter = ptr::null_mut();
+				if factory.EnumAdapters1(i, &mut adapter) != DXGI_ERROR_NOT_FOUND {
+					Some(unsafe { UniqueCOMPtr::from_ptr(adapter) })
+				} else { None } })
+			.take_while(Option::is_some).map(Option::unwrap)
+			.collect();
+
+		let mut index = capture_source_index;
+		for mut adapter in adapters {
+			let mut outputs = get_adater_outputs(&mut adapter);
+			if index >= outputs.len() {
+				index -= outputs.len();
+				continue;
+			}
+			let output = outputs.swap_remove(index);
+			return Self::create_duplication(adapter, output);
+		}
+		Err("capture_source_index is out of range of the available display outputs")
+	}
+
+	fn create_duplication(mut adapter: UniqueCOMPtr<IDXGIAdapter1>, output: UniqueCOMPtr<IDXGIOutput>)
+		-> Result<DuplicatedOutput, &'static str>
+	{
+		let (d3d11_device, device_context) = unsafe {
+			let mut d3d11_device: *mut ID3D11Device = ptr::null_mut();
+			let mut device_context: *mut ID3D11DeviceContext = ptr::null_mut();
+			let hr = D3D11CreateDevice(mem::transmute::<&mut IDXGIAdapter1, _>(&mut adapter),
+				D3D_DRIVER_TYPE::D3D_DRIVER_TYPE_UNKNOWN,
+				ptr::null_mut(), 0, ptr::null_mut(), 0,
+				D3D11_SDK_VERSION,
+				&mut d3d11_device,
+				&mut D3D_FEATURE_LEVEL::D3D_FEATURE_LEVEL_9_1,
+				&mut device_context);
+			if hr_failed(hr) {
+				return Err("Failed to create D3D11 device");
+			}
+			(UniqueCOMPtr::from_ptr(d3d11_device), UniqueCOMPtr::from_ptr(device_context)) };
+
+		let mut output: UniqueCOMPtr<IDXGIOutput1> = match unsafe {
+			output.query_interface(&IID_IDXGIOutput1) }
+		{
+			Ok(output) => output,
+			Err(_) => return Err("Output does not support IDXGIOutput1"),
+		};
+
+		let mut dxgi_device = match unsafe {
+			d3d11_device.query_interface::<IDXGIDevice1>(&IID_IDXGIDevice1) }
+		{
+			Ok(dxgi_device) => dxgi_device,
+			Err(_) => return Err("Failed to query IDXGIDevice1"),
+		};
+
+		let dxgi_output_dup = unsafe {
+			let mut dxgi_output_dup: *mut IDXGIOutputDuplication = ptr::null_mut();
+			let hr = output.DuplicateOutput(
+				mem::transmute::<&mut IDXGIDevice1, _>(&mut dxgi_device),
+				&mut dxgi_output_dup);
+			if hr_failed(hr) {
+				return Err("Failed to duplicate output");
+			}
+			UniqueCOMPtr::from_ptr(dxgi_output_dup) };
+
+		let d3d11_device = match unsafe {
+			dxgi_device.query_interface::<ID3D11Device>(&IID_ID3D11Device) }
+		{
+			Ok(d3d11_device) => d3d11_device,
+			Err(_) => return Err("Failed to query ID3D11Device"),
+		};
+
+		Ok(DuplicatedOutput {
+			device: Arc::new(Mutex::new(d3d11_device)),
+			device_context: Arc::new(Mutex::new(device_context)),
+			output: output,
+			dxgi_output_dup: dxgi_output_dup,
+			cached_pointer_shape: None,
+		})
+	}
+}
+
 #[test]
 fn test() {
 	use libc::{ c_void };
-	use dxgi::{ CreateDXGIFactory1, IID_IDXGIFactory1, IID_IDXGIOutput1,
-		IID_IDXGIDevice1, DXGI_ERROR_NOT_FOUND };
-	use d3d11::{ D3D_DRIVER_TYPE, D3D11_SDK_VERSION, D3D_FEATURE_LEVEL,
-		D3D11CreateDevice, ID3D11DeviceContext, IID_ID3D11Device };
 
 	let mut factory = unsafe {
 		let mut factory: *mut c_void = ptr::null_mut();
@@ -265,7 +1293,8 @@ fn test() {
 				DuplicatedOutput { device: d3d11_device,
 					device_context: device_context,
 					output: output,
-					dxgi_output_dup: duplicated_output }
+					dxgi_output_dup: duplicated_output,
+					cached_pointer_shape: None }
 			})
 			.collect();
 	}